@@ -1,17 +1,25 @@
 use {
-    addr2line::{fallible_iterator::FallibleIterator, Loader},
-    clap::Parser,
+    addr2line::{
+        fallible_iterator::FallibleIterator,
+        gimli::{
+            BaseAddresses, CfaRule, EhFrame, Register, RegisterRule, RunTimeEndian, UnwindContext,
+            UnwindSection, X86_64,
+        },
+        Loader,
+    },
+    clap::{Parser, ValueEnum},
     clap_num::maybe_hex,
     color_eyre::eyre::{eyre, Context, Result},
-    core::str,
+    flate2::{write::GzEncoder, Compression},
     itertools::Itertools,
+    object::{Object, ObjectSection},
     qapi::{
         futures::{QapiService, QmpStreamTokio},
-        qmp,
+        qga, qmp,
     },
     regex::Regex,
-    std::time::{Duration, Instant},
-    tokio::{io::WriteHalf, net::UnixStream, signal::ctrl_c},
+    std::{collections::HashMap, io::Write, path::PathBuf, sync::LazyLock, time::{Duration, Instant}},
+    tokio::{io::WriteHalf, net::UnixStream, signal::ctrl_c, sync::mpsc},
 };
 
 const RECURSIVE_FUNCTION_PATTERNS: &[&[&str]] = &[&[
@@ -20,10 +28,283 @@ const RECURSIVE_FUNCTION_PATTERNS: &[&[&str]] = &[&[
     "kernel::dbt::translate::FunctionTranslator::translate_statement",
 ]];
 
-#[derive(Debug)]
-struct StackFrame {
-    rbp: u64,
-    rip: u64,
+/// Callee-saved registers recovered while unwinding, in addition to the
+/// return-address and stack-pointer columns which are handled explicitly.
+const CALLEE_SAVED: &[Register] = &[
+    X86_64::RBX,
+    X86_64::RBP,
+    X86_64::R12,
+    X86_64::R13,
+    X86_64::R14,
+    X86_64::R15,
+];
+
+/// The subset of the x86-64 register file needed to drive CFI unwinding.
+///
+/// Indexed by DWARF register number so a [`Register`] can be looked up
+/// directly; only the low columns (through the return-address register) are
+/// ever populated.
+#[derive(Clone, Default)]
+struct Registers {
+    regs: [Option<u64>; 17],
+}
+
+impl Registers {
+    fn get(&self, reg: Register) -> Option<u64> {
+        self.regs.get(usize::from(reg.0)).copied().flatten()
+    }
+
+    fn set(&mut self, reg: Register, value: u64) {
+        if let Some(slot) = self.regs.get_mut(usize::from(reg.0)) {
+            *slot = Some(value);
+        }
+    }
+
+    fn clear(&mut self, reg: Register) {
+        if let Some(slot) = self.regs.get_mut(usize::from(reg.0)) {
+            *slot = None;
+        }
+    }
+}
+
+/// A single bulk capture of the guest stack, read in one QMP round-trip so
+/// individual frames resolve in-process.
+struct StackWindow {
+    /// Guest address of the first captured word.
+    base: u64,
+    /// Captured words, ascending from `base`.
+    words: Vec<u64>,
+}
+
+impl StackWindow {
+    /// Dump `size` bytes of stack starting at `base` with a single `x` command.
+    async fn capture(
+        qmp: &QapiService<QmpStreamTokio<WriteHalf<UnixStream>>>,
+        cpu_index: i64,
+        base: u64,
+        size: usize,
+    ) -> Result<Self> {
+        let count = (size / 8).max(1);
+        let dump = qmp
+            .execute(&qmp::human_monitor_command {
+                cpu_index: Some(cpu_index),
+                command_line: format!("x /{count}g {base:#x}"),
+            })
+            .await?;
+
+        // every printed value is prefixed `0x`; the line's address is not
+        static VALUE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"0x([0-9a-fA-F]+)").unwrap());
+        let words = VALUE
+            .captures_iter(&dump)
+            .map(|caps| u64::from_str_radix(&caps[1], 16))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { base, words })
+    }
+
+    /// Read a word from the window, or `None` when `addr` falls outside it.
+    fn get(&self, addr: u64) -> Option<u64> {
+        let delta = addr.checked_sub(self.base)?;
+        if delta % 8 != 0 {
+            return None;
+        }
+        self.words.get((delta / 8) as usize).copied()
+    }
+}
+
+/// One loaded section of a module, mapping a guest address range onto the
+/// address space the module's DWARF uses.
+struct SectionLoad {
+    /// First guest address the section occupies.
+    load_base: u64,
+    /// Size of the section in bytes.
+    size: u64,
+    /// Address of the section in the DWARF; for a relocatable `.ko` every
+    /// section is based at zero, so this is the section-relative origin.
+    dwarf_base: u64,
+}
+
+/// A single loaded image in the guest's address space.
+struct Module {
+    /// Allocated sections, each carrying its own guest range and DWARF origin.
+    sections: Vec<SectionLoad>,
+    loader: Loader,
+}
+
+impl Module {
+    /// Translate a guest address to the DWARF address of the section that
+    /// contains it, or `None` when the address is outside every section.
+    fn translate(&self, rip: u64) -> Option<u64> {
+        self.sections
+            .iter()
+            .find(|section| section.load_base <= rip && rip < section.load_base + section.size)
+            .map(|section| section.dwarf_base + (rip - section.load_base))
+    }
+}
+
+/// The guest's loaded images, used to map each RIP back to the right DWARF
+/// file and load offset.
+struct AddressSpace {
+    modules: Vec<Module>,
+}
+
+impl AddressSpace {
+    /// Seed the address space with the primary executable.
+    fn new(base: u64, path: &std::path::Path) -> Result<Self> {
+        Ok(Self {
+            modules: vec![load_module(base, path)?],
+        })
+    }
+
+    /// Load a module at `base` and insert it. A module whose DWARF can't be
+    /// opened or parsed is skipped with a warning rather than aborting the run.
+    /// Each call appends; existing entries are never replaced or removed, and
+    /// lookups use the first module whose sections cover an address.
+    fn insert(&mut self, base: u64, path: &std::path::Path) {
+        match load_module(base, path) {
+            Ok(module) => self.modules.push(module),
+            Err(e) => eprintln!("failed to load module {path:?}: {e:?}"),
+        }
+    }
+
+    /// Symbolize a single RIP into a `;`-joined chain of (possibly inlined)
+    /// frames, or `???` when no module or function covers it.
+    fn symbol(&self, rip: u64) -> String {
+        let Some((module, dwarf)) = self
+            .modules
+            .iter()
+            .find_map(|module| module.translate(rip).map(|dwarf| (module, dwarf)))
+        else {
+            return "???".into();
+        };
+
+        let frames = module
+            .loader
+            .find_frames(dwarf)
+            .expect("failed to find frames");
+
+        let frames = frames.collect::<Vec<_>>().unwrap();
+
+        if frames.is_empty() {
+            return "???".into();
+        }
+
+        // a frame without a demangleable function name (asm trampolines,
+        // stripped modules) is valid input in the streaming consumer, so fall
+        // back to `???` rather than panicking and aborting the run
+        frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                frame
+                    .function
+                    .as_ref()
+                    .and_then(|function| function.demangle().ok())
+                    .map(|name| name.into_owned())
+                    .unwrap_or_else(|| "???".into())
+            })
+            .join(";")
+    }
+}
+
+/// Open a module's DWARF and build its section load map from `base`.
+fn load_module(base: u64, path: &std::path::Path) -> Result<Module> {
+    let loader = Loader::new(path).map_err(|e| eyre!("Failed to load DWARF info: {e:?}"))?;
+
+    let data = std::fs::read(path).wrap_err_with(|| format!("Failed to read {path:?}"))?;
+    let object = object::File::parse(&*data).map_err(|e| eyre!("Failed to parse {path:?}: {e:?}"))?;
+
+    // a relocatable `.ko` has all-zero section VMAs and is loaded as a
+    // contiguous blob, so lay its allocated sections out by size from `base`;
+    // an executable keeps its own VMAs
+    let relocatable = object.kind() == object::ObjectKind::Relocatable;
+    let mut cursor = 0u64;
+    let mut sections = Vec::new();
+
+    for section in object.sections() {
+        let allocated = matches!(
+            section.flags(),
+            object::SectionFlags::Elf { sh_flags } if sh_flags & u64::from(object::elf::SHF_ALLOC) != 0
+        );
+        let size = section.size();
+        if !allocated || size == 0 {
+            continue;
+        }
+
+        let load_offset = if relocatable {
+            cursor = cursor.next_multiple_of(section.align().max(1));
+            let offset = cursor;
+            cursor += size;
+            offset
+        } else {
+            section.address()
+        };
+
+        sections.push(SectionLoad {
+            load_base: base + load_offset,
+            size,
+            dwarf_base: section.address(),
+        });
+    }
+
+    Ok(Module { sections, loader })
+}
+
+/// Query the guest agent for its loaded kernel modules, returning `(name,
+/// base address)` pairs parsed from `/proc/modules`.
+async fn query_modules(socket: &str) -> Result<Vec<(String, u64)>> {
+    let stream = qapi::futures::QgaStreamTokio::open_uds(socket)
+        .await
+        .wrap_err_with(|| format!("Failed to connect to guest agent socket {socket:?}"))?;
+    let (qga, _handle) = stream.spawn_tokio();
+
+    let exec = qga
+        .execute(&qga::guest_exec {
+            path: "/bin/cat".into(),
+            arg: Some(vec!["/proc/modules".into()]),
+            env: None,
+            input_data: None,
+            capture_output: Some(true),
+        })
+        .await?;
+
+    // poll until the command finishes
+    let output = loop {
+        let status = qga.execute(&qga::guest_exec_status { pid: exec.pid }).await?;
+        if status.exited {
+            break status.out_data.unwrap_or_default();
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+
+    let mut modules = Vec::new();
+    for line in String::from_utf8_lossy(&output).lines() {
+        // name size refcount deps state base
+        let mut fields = line.split_whitespace();
+        let Some(name) = fields.next() else {
+            continue;
+        };
+        if let Some(base) = fields
+            .last()
+            .and_then(|field| field.strip_prefix("0x"))
+            .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+        {
+            modules.push((name.to_owned(), base));
+        }
+    }
+
+    Ok(modules)
+}
+
+/// DWARF CFI unwinder driven by the executable's `.eh_frame` section.
+struct Unwinder {
+    eh_frame: Vec<u8>,
+    bases: BaseAddresses,
+    endian: RunTimeEndian,
+    /// Load offset applied to every RIP to map it back into the module.
+    offset: u64,
+    /// Bytes of stack captured per sample.
+    stack_size: usize,
 }
 
 #[derive(Parser, Debug)]
@@ -45,15 +326,62 @@ struct Args {
     /// Executable base address/load offset
     #[arg(short, long, value_parser=maybe_hex::<u64>)]
     offset: u64,
+
+    /// Emit a separate fold per vCPU instead of merging all CPUs together
+    #[arg(long)]
+    per_cpu: bool,
+
+    /// Write output to a file (rewritten on each flush) instead of stdout
+    #[arg(long)]
+    output: Option<String>,
+
+    /// Bytes of stack to capture per sample in a single memory dump
+    #[arg(long, default_value_t = 0x10000, value_parser=maybe_hex::<usize>)]
+    stack_size: usize,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = Format::Collapsed)]
+    format: Format,
+
+    /// Path to the QEMU guest-agent socket, used to resolve loaded guest modules
+    #[arg(short, long)]
+    guest_agent: Option<String>,
+
+    /// Directory holding module DWARF files (`<name>.ko`) named as in the guest
+    #[arg(long, default_value = ".")]
+    module_dir: PathBuf,
+}
+
+/// Supported collapsed-profile output backends.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    /// Brendan Gregg collapsed text (`frame;frame count`), for flamegraph.pl
+    Collapsed,
+    /// speedscope JSON (`sampled` schema)
+    Speedscope,
+    /// gzipped pprof protobuf
+    Pprof,
 }
 
+/// Number of samples symbolized between periodic flushes to the output file.
+const FLUSH_INTERVAL: u64 = 1000;
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     color_eyre::install()?;
     let args = Args::parse();
 
-    let debug = addr2line::Loader::new(args.executable)
-        .map_err(|e| eyre!("Failed to load DWARF info: {e:?}"))?;
+    // the primary executable is the first module; the guest agent fills in the
+    // rest of the address space
+    let mut address_space =
+        AddressSpace::new(args.offset, std::path::Path::new(&args.executable))?;
+    if let Some(socket) = &args.guest_agent {
+        for (name, base) in query_modules(socket).await? {
+            address_space.insert(base, &args.module_dir.join(format!("{name}.ko")));
+        }
+    }
+
+    let unwinder = Unwinder::load(&args.executable, args.offset, args.stack_size)?;
 
     // connect to QMP socket
     let stream = qapi::futures::QmpStreamTokio::open_uds(&args.socket)
@@ -67,172 +395,616 @@ async fn main() -> Result<()> {
         .wrap_err("Failed to negotiate stream")?;
     let (qmp, _handle) = stream.spawn_tokio();
 
-    let mut stacks = Vec::new();
+    // enumerate the guest CPUs once; the set is fixed for the run
+    let cpus = qmp
+        .execute(&qmp::query_cpus_fast {})
+        .await
+        .wrap_err("Failed to query guest CPUs")?
+        .into_iter()
+        .map(|cpu| cpu.cpu_index)
+        .collect::<Vec<_>>();
 
-    tokio::select! {
-        // should never terminate
-        _ = run_loop(&qmp, args.frequency,  &mut stacks) => {
-            Ok(())
-        },
-        // print map and terminate on exit
-        _ = ctrl_c() => {
-            eprintln!("exiting!");
-          //  pause_guest(&qmp).await?;// not necessary, but convenient
-            print_stacks(&stacks, &debug, args.offset)?;
-            Ok(())
-        },
+    // the sampler produces raw RIP stacks as fast as the monitor allows; a
+    // separate consumer owns the `Loader` and symbolizes off the hot path so
+    // DWARF lookups never steal time from the fixed-frequency interval
+    let (tx, rx) = mpsc::channel(1024);
+
+    let sampler = run_loop(&qmp, &unwinder, args.frequency, &cpus, tx);
+    let consumer = consume(
+        rx,
+        &address_space,
+        args.per_cpu,
+        args.output.as_deref(),
+        args.format,
+    );
+
+    // the sampler stops itself on Ctrl-C and drops its sender, which ends the
+    // consumer once the channel drains
+    tokio::try_join!(sampler, consumer)?;
+
+    Ok(())
+}
+
+impl Unwinder {
+    /// Read the executable's `.eh_frame` section up front so unwinding never
+    /// touches the filesystem on the sampling hot path.
+    fn load(path: &str, offset: u64, stack_size: usize) -> Result<Self> {
+        let data = std::fs::read(path)
+            .wrap_err_with(|| format!("Failed to read executable {path:?}"))?;
+        let object = object::File::parse(&*data)
+            .map_err(|e| eyre!("Failed to parse executable: {e:?}"))?;
+
+        let endian = if object.is_little_endian() {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+
+        let section = object
+            .section_by_name(".eh_frame")
+            .ok_or_else(|| eyre!("executable has no .eh_frame section"))?;
+        let eh_frame = section
+            .uncompressed_data()
+            .map_err(|e| eyre!("Failed to read .eh_frame: {e:?}"))?
+            .into_owned();
+
+        let bases = BaseAddresses::default()
+            .set_eh_frame(section.address())
+            .set_text(
+                object
+                    .section_by_name(".text")
+                    .map(|section| section.address())
+                    .unwrap_or_default(),
+            );
+
+        Ok(Self {
+            eh_frame,
+            bases,
+            endian,
+            offset,
+            stack_size,
+        })
+    }
+
+    /// Walk the stack starting from `regs` using CFI, returning the RIP of each
+    /// frame from innermost to outermost.
+    async fn unwind(
+        &self,
+        qmp: &QapiService<QmpStreamTokio<WriteHalf<UnixStream>>>,
+        cpu_index: i64,
+        mut regs: Registers,
+    ) -> Result<Vec<u64>> {
+        let eh_frame = EhFrame::new(&self.eh_frame, self.endian);
+        let mut ctx = UnwindContext::new();
+
+        // capture the whole live stack once; unwinding reads against this
+        // buffer and only falls back to QMP for addresses outside the window
+        let window = match regs.get(X86_64::RSP) {
+            Some(rsp) => Some(StackWindow::capture(qmp, cpu_index, rsp, self.stack_size).await?),
+            None => None,
+        };
+
+        let mut stack = Vec::new();
+        let mut previous_cfa: Option<u64> = None;
+
+        loop {
+            let rip = match regs.get(X86_64::RA) {
+                Some(rip) if rip != 0 => rip,
+                _ => break,
+            };
+            stack.push(rip);
+
+            // the innermost RIP is a live PC, but every outer frame's RIP is a
+            // return address pointing past the `call`; back it up by one so the
+            // lookup lands in the calling instruction's FDE, not the next
+            // function's (matters for `noreturn`/tail calls)
+            let lookup = if stack.len() == 1 { rip } else { rip - 1 };
+
+            // unwind info is keyed by module-relative addresses
+            let pc = lookup.wrapping_sub(self.offset);
+
+            let fde = match eh_frame.fde_for_address(&self.bases, pc, EhFrame::cie_from_offset) {
+                Ok(fde) => fde,
+                // nothing covers this address (hand-written asm, corrupt stack, ...)
+                Err(_) => break,
+            };
+            let return_address_register = fde.cie().return_address_register();
+
+            // pull everything we need out of the unwind row before awaiting on memory
+            let (cfa_register, cfa_offset, register_rules, return_address_rule) = {
+                let row = fde.unwind_info_for_address(&eh_frame, &self.bases, &mut ctx, pc)?;
+
+                let (cfa_register, cfa_offset) = match row.cfa() {
+                    CfaRule::RegisterAndOffset { register, offset } => (*register, *offset),
+                    // expression-based CFAs would need a full DWARF evaluator
+                    CfaRule::Expression(_) => break,
+                };
+
+                let register_rules = CALLEE_SAVED
+                    .iter()
+                    .map(|&reg| (reg, row.register(reg)))
+                    .collect::<Vec<_>>();
+
+                (
+                    cfa_register,
+                    cfa_offset,
+                    register_rules,
+                    row.register(return_address_register),
+                )
+            };
+
+            let cfa = regs
+                .get(cfa_register)
+                .ok_or_else(|| eyre!("CFA register {cfa_register:?} has no known value"))?
+                .wrapping_add(cfa_offset as u64);
+
+            // a non-advancing CFA means a corrupt frame; stop before looping forever
+            if let Some(previous) = previous_cfa {
+                if cfa <= previous {
+                    break;
+                }
+            }
+
+            // the caller's stack pointer is the CFA by definition
+            let mut next = regs.clone();
+            next.set(X86_64::RSP, cfa);
+
+            for (reg, rule) in &register_rules {
+                match apply_rule(qmp, cpu_index, window.as_ref(), rule, cfa, *reg, &regs).await? {
+                    Some(value) => next.set(*reg, value),
+                    None => next.clear(*reg),
+                }
+            }
+
+            match apply_rule(
+                qmp,
+                cpu_index,
+                window.as_ref(),
+                &return_address_rule,
+                cfa,
+                return_address_register,
+                &regs,
+            )
+            .await?
+            {
+                Some(0) | None => break,
+                Some(ra) => next.set(X86_64::RA, ra),
+            }
+
+            previous_cfa = Some(cfa);
+            regs = next;
+        }
+
+        Ok(stack)
     }
 }
 
+/// Recover a single register's caller value from its [`RegisterRule`].
+async fn apply_rule(
+    qmp: &QapiService<QmpStreamTokio<WriteHalf<UnixStream>>>,
+    cpu_index: i64,
+    window: Option<&StackWindow>,
+    rule: &RegisterRule<usize>,
+    cfa: u64,
+    reg: Register,
+    regs: &Registers,
+) -> Result<Option<u64>> {
+    Ok(match rule {
+        RegisterRule::Undefined => None,
+        RegisterRule::SameValue => regs.get(reg),
+        RegisterRule::Offset(offset) => {
+            Some(read_word(qmp, cpu_index, window, cfa.wrapping_add(*offset as u64)).await?)
+        }
+        RegisterRule::ValOffset(offset) => Some(cfa.wrapping_add(*offset as u64)),
+        RegisterRule::Register(other) => regs.get(*other),
+        _ => return Err(eyre!("unsupported register rule: {rule:?}")),
+    })
+}
+
 async fn run_loop(
     qmp: &QapiService<QmpStreamTokio<WriteHalf<UnixStream>>>,
+    unwinder: &Unwinder,
     frequency: u64,
-    stacks: &mut Vec<Vec<u64>>,
+    cpus: &[i64],
+    tx: mpsc::Sender<(i64, Vec<u64>)>,
 ) -> Result<()> {
-    // regex for extracting registers out of `info registers` command output
-    let rbp_regex = Regex::new(r"RBP=([0-9a-f]+)").unwrap();
-
     // interval between samples
     let mut interval = tokio::time::interval(Duration::from_nanos(1_000_000_000 / frequency));
 
     loop {
-        // interval not sleep, so no drift over time
-        interval.tick().await;
+        // interval not sleep, so no drift over time; bail out cleanly on Ctrl-C
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = ctrl_c() => {
+                eprintln!("exiting!");
+                break;
+            }
+        }
 
         let start = Instant::now();
 
         pause_guest(qmp).await?;
 
-        // get all register values
-        let registers = qmp
-            .execute(&qmp::human_monitor_command {
-                cpu_index: None,
-                command_line: "info registers".into(),
-            })
-            .await?;
-
-        let rbp = {
-            let caps = rbp_regex
-                .captures(&registers)
-                .ok_or(eyre!("Regex failed to find matches"))?;
+        let mut depth = 0;
 
-            // parse hex
-            u64::from_str_radix(&caps[1], 16)?
-        };
+        // walk every vCPU while the guest is stopped
+        for &cpu in cpus {
+            // get all register values for this CPU
+            let registers = qmp
+                .execute(&qmp::human_monitor_command {
+                    cpu_index: Some(cpu),
+                    command_line: "info registers".into(),
+                })
+                .await?;
 
-        let mut stack = vec![];
-        let mut current_bp = rbp;
+            // seed the unwinder with the live register file
+            let mut regs = Registers::default();
+            for (name, reg) in [
+                ("RIP", X86_64::RA),
+                ("RSP", X86_64::RSP),
+                ("RBP", X86_64::RBP),
+                ("RBX", X86_64::RBX),
+                ("R12", X86_64::R12),
+                ("R13", X86_64::R13),
+                ("R14", X86_64::R14),
+                ("R15", X86_64::R15),
+            ] {
+                regs.set(reg, register_from_monitor(&registers, name)?);
+            }
 
-        // iterate over stack frames
-        while current_bp != 0 {
-            let frame = get_stack_frame(qmp, current_bp).await?;
-            stack.push(frame.rip);
-            current_bp = frame.rbp;
-        }
+            let stack = unwinder.unwind(qmp, cpu, regs).await?;
 
-        let depth = stack.len();
+            depth += stack.len();
 
-        stacks.push(stack);
+            // hand the raw stack off to the consumer; a closed channel means
+            // the consumer is gone and there's nothing left to do
+            if tx.send((cpu, stack)).await.is_err() {
+                return Ok(());
+            }
+        }
 
         resume_guest(qmp).await?;
 
         let end = Instant::now();
 
-        eprintln!("depth: {depth}, avg {}us", (end - start).as_micros());
+        eprintln!(
+            "cpus: {}, total depth: {depth}, avg {}us",
+            cpus.len(),
+            (end - start).as_micros()
+        );
     }
+
+    Ok(())
+}
+
+/// Extract a named register out of `info registers` monitor output.
+fn register_from_monitor(output: &str, name: &str) -> Result<u64> {
+    // parsing the hex field directly avoids recompiling a regex for every
+    // register on the paused-guest hot path
+    let start = output
+        .find(&format!("{name}="))
+        .ok_or_else(|| eyre!("register {name} not found in monitor output"))?
+        + name.len()
+        + 1;
+    let hex = output[start..]
+        .split(|c: char| !c.is_ascii_hexdigit())
+        .next()
+        .unwrap_or_default();
+    Ok(u64::from_str_radix(hex, 16)?)
 }
 
-async fn get_stack_frame(
+/// Read a single 64-bit word of guest memory, preferring the captured stack
+/// window and falling back to an on-demand monitor `x` read.
+async fn read_word(
     qmp: &QapiService<QmpStreamTokio<WriteHalf<UnixStream>>>,
-    guest_ptr: u64,
-) -> Result<StackFrame> {
+    cpu_index: i64,
+    window: Option<&StackWindow>,
+    addr: u64,
+) -> Result<u64> {
+    if let Some(value) = window.and_then(|window| window.get(addr)) {
+        return Ok(value);
+    }
+
     let dump = qmp
         .execute(&qmp::human_monitor_command {
-            cpu_index: None,
-            command_line: format!("x /2g {guest_ptr:#x}"),
+            cpu_index: Some(cpu_index),
+            command_line: format!("x /1g {addr:#x}"),
         })
         .await?;
-    let rbp = u64::from_str_radix(str::from_utf8(&dump.as_bytes()[0x14..0x24])?, 16)?;
-    let rip = u64::from_str_radix(str::from_utf8(&dump.as_bytes()[0x27..0x37])?, 16)?;
+    let value = dump
+        .rsplit("0x")
+        .next()
+        .ok_or_else(|| eyre!("unexpected memory dump format: {dump:?}"))?
+        .trim();
+    Ok(u64::from_str_radix(value, 16)?)
+}
+
+/// Consume raw RIP stacks, symbolize them, and maintain a running count table,
+/// periodically flushing the rendered output when it goes to a file.
+async fn consume(
+    mut rx: mpsc::Receiver<(i64, Vec<u64>)>,
+    address_space: &AddressSpace,
+    per_cpu: bool,
+    output: Option<&str>,
+    format: Format,
+) -> Result<()> {
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    let mut since_flush = 0;
+
+    while let Some((cpu, stack)) = rx.recv().await {
+        let folded = collapse_stack(&stack, address_space);
+
+        // per-CPU folds prepend a synthetic `cpu_N` root so flamegraphs split
+        // by CPU; otherwise every CPU's samples are merged into one fold
+        let key = if per_cpu {
+            format!("cpu_{cpu};{folded}")
+        } else {
+            folded
+        };
+
+        *counts.entry(key).or_default() += 1;
+
+        // periodic flushes rewrite a file in place; stdout would instead
+        // accumulate concatenated snapshots no tool can parse, so only flush
+        // early when writing to a file
+        since_flush += 1;
+        if output.is_some() && since_flush >= FLUSH_INTERVAL {
+            flush(&counts, output, format)?;
+            since_flush = 0;
+        }
+    }
 
-    let frame = StackFrame { rbp, rip };
+    // final flush once the sampler has hung up
+    flush(&counts, output, format)?;
 
-    Ok(frame)
+    Ok(())
 }
 
-fn print_stacks(stacks: &Vec<Vec<u64>>, debug: &Loader, offset: u64) -> Result<()> {
-    stacks
-        .iter()
-        .map(|stack| {
-            let mut symbols = stack
-                .iter()
-                .rev()
-                .map(|rip| {
-                    let frames = debug
-                        .find_frames(rip - offset)
-                        .expect("failed to find frames");
-
-                    let frames = frames.collect::<Vec<_>>().unwrap();
-
-                    match frames.len() {
-                        0 => "???".into(),
-                        1 => frames[0]
-                            .function
-                            .as_ref()
-                            .expect("function field in frame was None")
-                            .demangle()
-                            .expect("failed to demangle")
-                            .into_owned(),
-                        _ => frames
-                            .into_iter()
-                            .rev()
-                            .map(|f| {
-                                f.function
-                                    .expect("function field in frame was None")
-                                    .demangle()
-                                    .expect("failed to demangle")
-                                    .into_owned()
-                            })
-                            .join(";"),
-                    }
+/// Render the count table in the requested format and write it to `output`, or
+/// stdout when unset.
+fn flush(counts: &HashMap<String, u64>, output: Option<&str>, format: Format) -> Result<()> {
+    let rendered = match format {
+        Format::Collapsed => render_collapsed(counts).into_bytes(),
+        Format::Speedscope => render_speedscope(counts)?.into_bytes(),
+        Format::Pprof => render_pprof(counts)?,
+    };
+
+    match output {
+        Some(path) => std::fs::write(path, rendered)
+            .wrap_err_with(|| format!("Failed to write output to {path:?}"))?,
+        None => std::io::stdout().write_all(&rendered)?,
+    }
+
+    Ok(())
+}
+
+/// Brendan Gregg collapsed text: one `frame;frame;frame count` line per stack.
+fn render_collapsed(counts: &HashMap<String, u64>) -> String {
+    let mut rendered = String::new();
+    for (ident, count) in counts {
+        rendered.push_str(&format!("{ident} {count}\n"));
+    }
+    rendered
+}
+
+/// speedscope `sampled` profile, sharing a single frame table across samples.
+fn render_speedscope(counts: &HashMap<String, u64>) -> Result<String> {
+    let mut frames: Vec<String> = Vec::new();
+    let mut frame_index: HashMap<String, usize> = HashMap::new();
+
+    let mut samples: Vec<Vec<usize>> = Vec::new();
+    let mut weights: Vec<u64> = Vec::new();
+    let mut total = 0;
+
+    for (folded, count) in counts {
+        let sample = folded
+            .split(';')
+            .map(|name| {
+                *frame_index.entry(name.to_owned()).or_insert_with(|| {
+                    let index = frames.len();
+                    frames.push(name.to_owned());
+                    index
                 })
-                .collect::<Vec<_>>()
-                .into_iter();
+            })
+            .collect();
+        samples.push(sample);
+        weights.push(*count);
+        total += *count;
+    }
 
-            let mut filtered_symbols = Vec::new();
+    let profile = serde_json::json!({
+        "$schema": "https://www.speedscope.app/file-format-schema.json",
+        "name": "qprofrs",
+        "activeProfileIndex": 0,
+        "exporter": "qprofrs",
+        "shared": {
+            "frames": frames.iter().map(|name| serde_json::json!({ "name": name })).collect::<Vec<_>>(),
+        },
+        "profiles": [{
+            "type": "sampled",
+            "name": "qprofrs",
+            "unit": "none",
+            "startValue": 0,
+            "endValue": total,
+            "samples": samples,
+            "weights": weights,
+        }],
+    });
 
-            while let Some(symbol) = symbols.next() {
-                if let Some(pattern) = RECURSIVE_FUNCTION_PATTERNS
-                    .iter()
-                    .find(|pattern| pattern[0] == symbol)
-                {
-                    // start of pattern
-
-                    // consume until doesn't match pattern
-                    let mut pattern_index = 1;
-                    while Some(pattern[pattern_index % pattern.len()]) == symbols.next().as_deref()
-                    {
-                        pattern_index += 1;
-                    }
-
-                    // insert one copy of pattern
-                    filtered_symbols.extend(
-                        pattern[..pattern_index & pattern.len()]
-                            .iter()
-                            .map(|s| (*s).to_owned()),
-                    );
-                } else {
-                    filtered_symbols.push(symbol);
-                }
+    Ok(serde_json::to_string(&profile)?)
+}
+
+/// gzipped pprof protobuf, with one function/location per distinct frame name.
+fn render_pprof(counts: &HashMap<String, u64>) -> Result<Vec<u8>> {
+    let mut strings = StringTable::new();
+    let sample_type_name = strings.intern("samples");
+    let sample_type_unit = strings.intern("count");
+
+    let mut function_ids: HashMap<String, u64> = HashMap::new();
+    let mut functions: Vec<(u64, i64)> = Vec::new();
+    let mut samples: Vec<(Vec<u64>, u64)> = Vec::new();
+
+    for (folded, count) in counts {
+        let mut location_ids = folded
+            .split(';')
+            .map(|name| {
+                *function_ids.entry(name.to_owned()).or_insert_with(|| {
+                    let id = functions.len() as u64 + 1;
+                    functions.push((id, strings.intern(name)));
+                    id
+                })
+            })
+            .collect::<Vec<_>>();
+        // pprof orders a sample's locations leaf-first
+        location_ids.reverse();
+        samples.push((location_ids, *count));
+    }
+
+    let mut profile = Vec::new();
+
+    // sample_type (field 1): ValueType { type, unit }
+    let mut value_type = Vec::new();
+    pb_varint_field(&mut value_type, 1, sample_type_name as u64);
+    pb_varint_field(&mut value_type, 2, sample_type_unit as u64);
+    pb_bytes_field(&mut profile, 1, &value_type);
+
+    // sample (field 2): Sample { location_id (packed), value (packed) }
+    for (location_ids, count) in &samples {
+        let mut sample = Vec::new();
+
+        let mut packed_locations = Vec::new();
+        for id in location_ids {
+            pb_varint(&mut packed_locations, *id);
+        }
+        pb_bytes_field(&mut sample, 1, &packed_locations);
+
+        let mut packed_values = Vec::new();
+        pb_varint(&mut packed_values, *count);
+        pb_bytes_field(&mut sample, 2, &packed_values);
+
+        pb_bytes_field(&mut profile, 2, &sample);
+    }
+
+    // location (field 4): Location { id, line { function_id } }
+    for (id, _) in &functions {
+        let mut location = Vec::new();
+        pb_varint_field(&mut location, 1, *id);
+
+        let mut line = Vec::new();
+        pb_varint_field(&mut line, 1, *id);
+        pb_bytes_field(&mut location, 4, &line);
+
+        pb_bytes_field(&mut profile, 4, &location);
+    }
+
+    // function (field 5): Function { id, name }
+    for (id, name) in &functions {
+        let mut function = Vec::new();
+        pb_varint_field(&mut function, 1, *id);
+        pb_varint_field(&mut function, 2, *name as u64);
+        pb_bytes_field(&mut profile, 5, &function);
+    }
+
+    // string_table (field 6)
+    for string in &strings.table {
+        pb_bytes_field(&mut profile, 6, string.as_bytes());
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&profile)?;
+    Ok(encoder.finish()?)
+}
+
+/// Deduplicating string table; index 0 is always the empty string as pprof
+/// requires.
+struct StringTable {
+    table: Vec<String>,
+    index: HashMap<String, i64>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        Self {
+            table: vec![String::new()],
+            index: HashMap::from([(String::new(), 0)]),
+        }
+    }
+
+    fn intern(&mut self, string: &str) -> i64 {
+        if let Some(index) = self.index.get(string) {
+            return *index;
+        }
+        let index = self.table.len() as i64;
+        self.table.push(string.to_owned());
+        self.index.insert(string.to_owned(), index);
+        index
+    }
+}
+
+/// Append a base-128 varint.
+fn pb_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let mut byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value != 0 {
+            byte |= 0x80;
+        }
+        buf.push(byte);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+/// Append a varint-typed field (wire type 0).
+fn pb_varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    pb_varint(buf, field << 3);
+    pb_varint(buf, value);
+}
+
+/// Append a length-delimited field (wire type 2).
+fn pb_bytes_field(buf: &mut Vec<u8>, field: u64, data: &[u8]) {
+    pb_varint(buf, (field << 3) | 2);
+    pb_varint(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+/// Symbolize a single RIP stack into a Brendan Gregg folded line.
+fn collapse_stack(stack: &[u64], address_space: &AddressSpace) -> String {
+    let mut symbols = stack
+        .iter()
+        .rev()
+        .map(|rip| address_space.symbol(*rip))
+        .collect::<Vec<_>>()
+        .into_iter();
+
+    let mut filtered_symbols = Vec::new();
+
+    while let Some(symbol) = symbols.next() {
+        if let Some(pattern) = RECURSIVE_FUNCTION_PATTERNS
+            .iter()
+            .find(|pattern| pattern[0] == symbol)
+        {
+            // start of pattern
+
+            // consume until doesn't match pattern
+            let mut pattern_index = 1;
+            while Some(pattern[pattern_index % pattern.len()]) == symbols.next().as_deref() {
+                pattern_index += 1;
             }
 
-            filtered_symbols.join(";")
-        })
-        .counts()
-        .into_iter()
-        .for_each(|(ident, count)| println!("{ident} {count}"));
+            // insert one copy of pattern
+            filtered_symbols.extend(
+                pattern[..pattern_index & pattern.len()]
+                    .iter()
+                    .map(|s| (*s).to_owned()),
+            );
+        } else {
+            filtered_symbols.push(symbol);
+        }
+    }
 
-    Ok(())
+    filtered_symbols.join(";")
 }
 
 async fn pause_guest(qmp: &QapiService<QmpStreamTokio<WriteHalf<UnixStream>>>) -> Result<()> {